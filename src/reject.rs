@@ -1,39 +1,161 @@
 //! Commonly used rejections and recovery procedures.
 use std::fmt::Display;
+use std::time::Duration;
 
-use axum::http::StatusCode;
-
-use axum::response::{IntoResponse, Response};
+use axum::extract::Request;
+use axum::http::header::{ACCEPT, CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
 
 use miette::Diagnostic;
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 
 use crate::reply;
 
-const MESSAGE_NOT_FOUND: &str = "not found";
 const MESSAGE_FORBIDDEN: &str = "forbidden";
+const MESSAGE_NOT_FOUND: &str = "not found";
 const MESSAGE_INTERNAL_SERVER_ERROR: &str = "internal server error";
 
+const PROBLEM_JSON: &str = "application/problem+json";
+
+tokio::task_local! {
+    static ACCEPT_HEADER: Option<HeaderValue>;
+}
+
+/// Axum middleware that records the request's `Accept` header so [`HTTPError::into_response`]
+/// can negotiate between RFC 7807 `application/problem+json` bodies and the legacy error shape.
+/// Add this as an outer layer (e.g. `axum::middleware::from_fn(negotiate_problem_json)`) on any
+/// router whose handlers return `HTTPError`.
+pub async fn negotiate_problem_json(request: Request, next: Next) -> Response {
+    let accept = request.headers().get(ACCEPT).cloned();
+    ACCEPT_HEADER.scope(accept, next.run(request)).await
+}
+
+/// An [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) Problem Details body.
+#[derive(Debug, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    type_: String,
+    title: String,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    /// A stable, machine-readable error code independent of the HTTP status
+    /// (the value of [`miette::Diagnostic::code`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    /// Human-readable guidance for resolving the error ([`miette::Diagnostic::help`]).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    help: Option<String>,
+}
+
+/// Whether the current request (as recorded by [`negotiate_problem_json`]) accepts
+/// `application/problem+json`. Defaults to `true` when no `Accept` header was recorded, so
+/// `application/problem+json` is the default error shape.
+fn wants_problem_json() -> bool {
+    ACCEPT_HEADER
+        .try_with(|accept| match accept {
+            Some(value) => value
+                .to_str()
+                .map(|s| s.contains(PROBLEM_JSON) || s.contains("*/*"))
+                .unwrap_or(true),
+            None => true,
+        })
+        .unwrap_or(true)
+}
+
+fn problem_response(
+    type_: &str,
+    status: StatusCode,
+    detail: String,
+    code: Option<String>,
+    help: Option<String>,
+) -> Response {
+    let problem = Problem {
+        type_: type_.to_string(),
+        title: status.canonical_reason().unwrap_or("Unknown").to_string(),
+        status: status.as_u16(),
+        detail,
+        instance: None,
+        code,
+        help,
+    };
+
+    let mut response = (status, Json(problem)).into_response();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    response
+}
+
 #[derive(Debug, Diagnostic, Serialize)]
 #[serde(untagged)]
 pub enum HTTPError {
-    BadRequest {
-        error: String,
-    },
+    #[diagnostic(
+        code(apikit::bad_request),
+        help("Check that the request body and parameters are well-formed.")
+    )]
+    BadRequest { error: String },
+    #[diagnostic(
+        code(apikit::unauthorized),
+        help("Provide valid credentials and retry.")
+    )]
+    Unauthorized,
+    #[diagnostic(
+        code(apikit::forbidden),
+        help("You do not have permission to perform this action.")
+    )]
     Forbidden,
+    #[diagnostic(
+        code(apikit::not_found),
+        help("Check that the requested resource exists.")
+    )]
     NotFound,
+    #[diagnostic(
+        code(apikit::conflict),
+        help("The request conflicts with the current state of the resource.")
+    )]
+    Conflict { error: String },
+    #[diagnostic(
+        code(apikit::unprocessable_entity),
+        help("The request was well-formed but its contents were semantically invalid.")
+    )]
+    UnprocessableEntity { error: String },
+    #[diagnostic(
+        code(apikit::too_many_requests),
+        help("Slow down and retry after the indicated delay.")
+    )]
+    TooManyRequests { retry_after: Option<Duration> },
+    #[diagnostic(
+        code(apikit::internal_server_error),
+        help("This is a bug in the service; please report it.")
+    )]
     InternalServerError {
         error: String,
         backtrace: Option<String>,
     },
+    /// Not an error: redirects the client to `location` via a `Location` header, so a handler
+    /// can send a redirect while still returning `Result<T, HTTPError>`.
+    Redirect { location: String, permanent: bool },
+    /// Catch-all for any status code without a dedicated variant.
+    Status(#[serde(serialize_with = "serialize_status_code")] StatusCode),
+}
+
+fn serialize_status_code<S: Serializer>(
+    status: &StatusCode,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
 }
 
 impl Display for HTTPError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::BadRequest { error } => write!(f, "bad request: {}", error),
-            Self::Forbidden => write!(f, "forbidden"),
-            Self::NotFound => write!(f, "not found"),
+            Self::Conflict { error } => write!(f, "conflict: {}", error),
+            Self::UnprocessableEntity { error } => write!(f, "unprocessable entity: {}", error),
             Self::InternalServerError {
                 error,
                 backtrace: Some(backtrace),
@@ -42,6 +164,16 @@ impl Display for HTTPError {
                 error,
                 backtrace: None,
             } => write!(f, "internal server error: {}", error),
+            Self::Redirect {
+                location,
+                permanent,
+            } => write!(
+                f,
+                "{} redirect to {}",
+                if *permanent { "permanent" } else { "temporary" },
+                location
+            ),
+            _ => write!(f, "{}", self.message().to_lowercase()),
         }
     }
 }
@@ -55,34 +187,446 @@ impl HTTPError {
         }
     }
 
+    pub fn unauthorized() -> Self {
+        Self::Unauthorized
+    }
+
+    pub fn conflict<S: ToString>(s: S) -> Self {
+        Self::Conflict {
+            error: s.to_string(),
+        }
+    }
+
+    pub fn unprocessable_entity<S: ToString>(s: S) -> Self {
+        Self::UnprocessableEntity {
+            error: s.to_string(),
+        }
+    }
+
+    pub fn too_many_requests(retry_after: Option<Duration>) -> Self {
+        Self::TooManyRequests { retry_after }
+    }
+
+    /// Redirects the client to `location` with a `302 Found`.
+    pub fn temporary_redirect<S: ToString>(location: S) -> Self {
+        Self::Redirect {
+            location: location.to_string(),
+            permanent: false,
+        }
+    }
+
+    /// Redirects the client to `location` with a `308 Permanent Redirect`.
+    pub fn permanent_redirect<S: ToString>(location: S) -> Self {
+        Self::Redirect {
+            location: location.to_string(),
+            permanent: true,
+        }
+    }
+
     pub fn internal_server_error<E: ToString>(e: E) -> Self {
         Self::InternalServerError {
             error: e.to_string(),
-            backtrace: None, // TODO: Properly capture backtrace
+            backtrace: capture_backtrace(None),
+        }
+    }
+
+    /// Like [`HTTPError::internal_server_error`], but also folds the `.source()` chain of `e`
+    /// into the captured backtrace, for errors that implement [`std::error::Error`].
+    pub fn internal_server_error_with_source<E: std::error::Error>(e: E) -> Self {
+        Self::InternalServerError {
+            error: e.to_string(),
+            backtrace: capture_backtrace(Some(&e)),
+        }
+    }
+
+    /// The HTTP status code this error maps to.
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::InternalServerError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Redirect { permanent, .. } => {
+                if *permanent {
+                    StatusCode::PERMANENT_REDIRECT
+                } else {
+                    StatusCode::FOUND
+                }
+            }
+            Self::Status(status) => *status,
+        }
+    }
+
+    /// The message surfaced to the client: the variant's own detail for `BadRequest`, `Conflict`
+    /// and `UnprocessableEntity`, otherwise the lowercased canonical reason phrase for its status
+    /// code. The detail of an `InternalServerError` is never surfaced.
+    fn message(&self) -> String {
+        match self {
+            Self::BadRequest { error }
+            | Self::Conflict { error }
+            | Self::UnprocessableEntity { error } => error.clone(),
+            Self::Forbidden => MESSAGE_FORBIDDEN.to_string(),
+            Self::NotFound => MESSAGE_NOT_FOUND.to_string(),
+            Self::InternalServerError { .. } => MESSAGE_INTERNAL_SERVER_ERROR.to_string(),
+            // No hand-written string for these: lowercase the canonical reason phrase so the
+            // wire-visible text stays consistent with the variants above (and with `Display`,
+            // which lowercases this same fallback).
+            _ => self
+                .status()
+                .canonical_reason()
+                .unwrap_or("unknown error")
+                .to_lowercase(),
+        }
+    }
+
+    /// A stable, per-variant URI reference identifying this error's class, for the `type` field
+    /// of the RFC 7807 body.
+    fn problem_type(&self) -> String {
+        match self {
+            Self::Status(_) => "about:blank".to_string(),
+            _ => format!(
+                "about:blank#{}",
+                self.status()
+                    .canonical_reason()
+                    .unwrap_or("error")
+                    .to_lowercase()
+                    .replace(' ', "-")
+            ),
         }
     }
 }
 
+/// Captures the current backtrace (respecting `RUST_BACKTRACE`/`RUST_LIB_BACKTRACE`), folding
+/// in the `.source()` chain of `source` when one is provided. The raw backtrace frames are
+/// omitted when capture is disabled (the common case in production), but the `.source()` chain
+/// is always folded in regardless, since it costs nothing to collect and `RUST_BACKTRACE` is
+/// about frame capture, not cause-chain reporting. Returns `None` only when there is neither a
+/// captured backtrace nor a source chain to report.
+fn capture_backtrace(source: Option<&dyn std::error::Error>) -> Option<String> {
+    let backtrace = std::backtrace::Backtrace::capture();
+    let mut trace = if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+        backtrace.to_string()
+    } else {
+        String::new()
+    };
+
+    let mut current = source.and_then(std::error::Error::source);
+    while let Some(cause) = current {
+        if !trace.is_empty() {
+            trace.push('\n');
+        }
+        trace.push_str("Caused by: ");
+        trace.push_str(&cause.to_string());
+        current = cause.source();
+    }
+
+    if trace.is_empty() {
+        None
+    } else {
+        Some(trace)
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl From<sqlx::Error> for HTTPError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            _ => Self::internal_server_error_with_source(e),
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for HTTPError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => Self::internal_server_error(format!(
+                "upstream request failed with status {}: {}",
+                status, e
+            )),
+            None => Self::internal_server_error_with_source(e),
+        }
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl From<serde_json::Error> for HTTPError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::bad_request(e)
+    }
+}
+
+#[cfg(feature = "validation")]
+impl From<std::str::Utf8Error> for HTTPError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Self::bad_request(e)
+    }
+}
+
+#[cfg(feature = "validation")]
+impl From<base64::DecodeError> for HTTPError {
+    fn from(e: base64::DecodeError) -> Self {
+        Self::bad_request(e)
+    }
+}
+
+impl From<StatusCode> for HTTPError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
 impl IntoResponse for HTTPError {
     fn into_response(self) -> Response {
-        match self {
-            Self::BadRequest { error } => reply::error(error, StatusCode::BAD_REQUEST),
-            Self::Forbidden => reply::error(MESSAGE_FORBIDDEN, StatusCode::FORBIDDEN),
-            Self::NotFound => reply::error(MESSAGE_NOT_FOUND, StatusCode::NOT_FOUND),
-            Self::InternalServerError {
-                ref error,
-                ref backtrace,
-            } => {
-                if let Some(backtrace) = backtrace {
-                    tracing::error!("{error}\n{backtrace}");
-                } else {
-                    tracing::error!("{error}");
+        if let Self::Redirect { location, .. } = &self {
+            return match HeaderValue::from_str(location) {
+                Ok(value) => {
+                    let mut response = self.status().into_response();
+                    response.headers_mut().insert(LOCATION, value);
+                    response
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "redirect location {location:?} is not a valid header value: {e}"
+                    );
+                    Self::internal_server_error("invalid redirect location").into_response()
                 }
-                reply::error(
-                    MESSAGE_INTERNAL_SERVER_ERROR,
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )
+            };
+        }
+
+        let code = Diagnostic::code(&self).map(|c| c.to_string());
+        let help = Diagnostic::help(&self).map(|h| h.to_string());
+
+        if let Self::InternalServerError { error, backtrace } = &self {
+            let code = code.as_deref().unwrap_or("unknown");
+            match backtrace {
+                Some(backtrace) => tracing::error!(code, "{error}\n{backtrace}"),
+                None => tracing::error!(code, "{error}"),
             }
         }
+
+        let status = self.status();
+        let problem_type = self.problem_type();
+        let retry_after = match &self {
+            Self::TooManyRequests { retry_after } => *retry_after,
+            _ => None,
+        };
+        let message = self.message();
+
+        let mut response = if wants_problem_json() {
+            problem_response(&problem_type, status, message, code, help)
+        } else {
+            reply::error(message, status)
+        };
+
+        if let Some(retry_after) = retry_after {
+            // Round up so a sub-second delay still tells the client to wait at least a second,
+            // rather than truncating to "0" and inviting an immediate retry.
+            let seconds = retry_after.as_secs_f64().ceil() as u64;
+            if let Ok(value) = HeaderValue::from_str(&seconds.to_string()) {
+                response.headers_mut().insert(RETRY_AFTER, value);
+            }
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_maps_each_variant_to_its_http_status() {
+        assert_eq!(
+            HTTPError::bad_request("x").status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(HTTPError::unauthorized().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(HTTPError::Forbidden.status(), StatusCode::FORBIDDEN);
+        assert_eq!(HTTPError::NotFound.status(), StatusCode::NOT_FOUND);
+        assert_eq!(HTTPError::conflict("x").status(), StatusCode::CONFLICT);
+        assert_eq!(
+            HTTPError::unprocessable_entity("x").status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            HTTPError::too_many_requests(None).status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            HTTPError::internal_server_error("x").status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            HTTPError::temporary_redirect("/a").status(),
+            StatusCode::FOUND
+        );
+        assert_eq!(
+            HTTPError::permanent_redirect("/a").status(),
+            StatusCode::PERMANENT_REDIRECT
+        );
+        assert_eq!(
+            HTTPError::from(StatusCode::IM_A_TEAPOT).status(),
+            StatusCode::IM_A_TEAPOT
+        );
+    }
+
+    #[test]
+    fn wants_problem_json_defaults_to_true_without_a_recorded_accept_header() {
+        assert!(wants_problem_json());
+    }
+
+    #[tokio::test]
+    async fn wants_problem_json_respects_an_explicit_accept_header() {
+        let accepts_legacy = ACCEPT_HEADER
+            .scope(Some(HeaderValue::from_static("application/json")), async {
+                wants_problem_json()
+            })
+            .await;
+        assert!(!accepts_legacy);
+
+        let accepts_problem = ACCEPT_HEADER
+            .scope(
+                Some(HeaderValue::from_static("application/problem+json")),
+                async { wants_problem_json() },
+            )
+            .await;
+        assert!(accepts_problem);
+
+        let accepts_wildcard = ACCEPT_HEADER
+            .scope(Some(HeaderValue::from_static("*/*")), async {
+                wants_problem_json()
+            })
+            .await;
+        assert!(accepts_wildcard);
+
+        let no_header_recorded = ACCEPT_HEADER
+            .scope(None, async { wants_problem_json() })
+            .await;
+        assert!(no_header_recorded);
+    }
+
+    #[test]
+    fn redirect_into_response_sets_status_and_location() {
+        let response = HTTPError::temporary_redirect("/login").into_response();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get(LOCATION).unwrap(), "/login");
+
+        let response = HTTPError::permanent_redirect("/canonical").into_response();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers().get(LOCATION).unwrap(), "/canonical");
+    }
+
+    #[test]
+    fn redirect_into_response_falls_back_to_500_on_invalid_location() {
+        let response = HTTPError::temporary_redirect("bad\nheader").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.headers().get(LOCATION).is_none());
+    }
+
+    #[test]
+    fn message_keeps_the_pre_existing_lowercase_text_for_forbidden_and_not_found() {
+        assert_eq!(HTTPError::Forbidden.message(), "forbidden");
+        assert_eq!(HTTPError::NotFound.message(), "not found");
+    }
+
+    #[test]
+    fn too_many_requests_rounds_a_sub_second_retry_after_up_to_a_whole_second() {
+        let response =
+            HTTPError::too_many_requests(Some(Duration::from_millis(500))).into_response();
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
+
+        let response = HTTPError::too_many_requests(Some(Duration::from_secs(5))).into_response();
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "5");
+    }
+
+    #[derive(Debug)]
+    struct WithSource {
+        source: std::io::Error,
+    }
+
+    impl Display for WithSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "outer error")
+        }
+    }
+
+    impl std::error::Error for WithSource {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.source)
+        }
+    }
+
+    #[test]
+    fn capture_backtrace_folds_the_source_chain_regardless_of_backtrace_capture_status() {
+        let err = WithSource {
+            source: std::io::Error::new(std::io::ErrorKind::Other, "inner cause"),
+        };
+
+        let trace =
+            capture_backtrace(Some(&err)).expect("a source chain should always produce a trace");
+        assert!(
+            trace.contains("Caused by: inner cause"),
+            "trace did not fold the source chain: {trace}"
+        );
+    }
+
+    #[test]
+    fn capture_backtrace_returns_none_without_a_source_chain_when_capture_is_disabled() {
+        // `Backtrace::capture` reads RUST_BACKTRACE/RUST_LIB_BACKTRACE once per process and
+        // caches the result, and other tests here also capture backtraces, so these settings
+        // take effect only if this test is the first to capture one.
+        std::env::set_var("RUST_LIB_BACKTRACE", "0");
+        std::env::set_var("RUST_BACKTRACE", "0");
+        assert_eq!(capture_backtrace(None), None);
+    }
+
+    #[tokio::test]
+    async fn problem_json_body_includes_type_title_status_detail_code_and_help() {
+        let response = HTTPError::bad_request("bad field").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(problem["type"], "about:blank#bad-request");
+        assert_eq!(problem["title"], "Bad Request");
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["detail"], "bad field");
+        assert_eq!(problem["code"], "apikit::bad_request");
+        assert_eq!(
+            problem["help"],
+            "Check that the request body and parameters are well-formed."
+        );
+    }
+
+    #[tokio::test]
+    async fn problem_json_body_masks_the_detail_for_internal_server_error() {
+        let response = HTTPError::internal_server_error("db exploded").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(problem["type"], "about:blank#internal-server-error");
+        assert_eq!(problem["title"], "Internal Server Error");
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["detail"], "internal server error");
+        assert_eq!(problem["code"], "apikit::internal_server_error");
+        assert_eq!(
+            problem["help"],
+            "This is a bug in the service; please report it."
+        );
     }
 }